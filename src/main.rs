@@ -1,33 +1,114 @@
 use std::collections::{HashMap, HashSet};
 use std::ops::Add;
 
+use serde::{Deserialize, Serialize};
+
+const MAX_COMPONENT_BITS: usize = 64;
+
 #[derive(Debug)]
 #[derive(Clone)]
-#[derive(Copy)]
+#[derive(PartialEq)]
+#[derive(Serialize, Deserialize)]
 enum FieldType {
     Integer(i64),
     Float(f64),
+    EntityRef(i64),
+    Text(String),
+    Boolean(bool),
+    List(Vec<FieldType>),
 }
 
 impl Add for FieldType {
     type Output = Self;
 
     fn add(self, other: Self) -> Self {
+        match (self, other) {
+            (FieldType::Integer(self_value), FieldType::Integer(other_value)) => FieldType::Integer(self_value + other_value),
+            (FieldType::Integer(self_value), FieldType::Float(other_value)) => FieldType::Float(self_value as f64 + other_value),
+            (FieldType::Float(self_value), FieldType::Integer(other_value)) => FieldType::Float(self_value + other_value as f64),
+            (FieldType::Float(self_value), FieldType::Float(other_value)) => FieldType::Float(self_value + other_value),
+            (FieldType::Text(self_value), FieldType::Text(other_value)) => FieldType::Text(self_value + &other_value),
+            (FieldType::List(mut self_value), FieldType::List(other_value)) => {
+                self_value.extend(other_value);
+                FieldType::List(self_value)
+            },
+            (self_value, _) => self_value,
+        }
+    }
+}
+
+impl FieldType {
+    fn is_numeric(&self) -> bool {
+        matches!(self, FieldType::Integer(_) | FieldType::Float(_))
+    }
+
+    fn as_f64(&self) -> f64 {
         match self {
-            FieldType::Integer(self_value) => {
-                match other {
-                    FieldType::Integer(other_value) => FieldType::Integer(self_value + other_value),
-                    FieldType::Float(other_value) => FieldType::Float(self_value as f64 + other_value),
-                }
-            }
-            FieldType::Float(self_value) => {
-                match other {
-                    FieldType::Integer(other_value) => FieldType::Float(self_value + other_value as f64),
-                    FieldType::Float(other_value) => FieldType::Float(self_value + other_value),
-                }
-            }
+            FieldType::Integer(value) => *value as f64,
+            FieldType::Float(value) => *value,
+            FieldType::EntityRef(entity_id) => *entity_id as f64,
+            FieldType::Text(_) | FieldType::Boolean(_) | FieldType::List(_) => 0.0,
+        }
+    }
+
+    fn get_int(&self) -> Option<i64> {
+        match self {
+            FieldType::Integer(value) => Some(*value),
+            _ => None,
+        }
+    }
+
+    fn get_float(&self) -> Option<f64> {
+        match self {
+            FieldType::Float(value) => Some(*value),
+            _ => None,
         }
     }
+
+    fn get_text(&self) -> Option<&str> {
+        match self {
+            FieldType::Text(value) => Some(value.as_str()),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug)]
+#[derive(Clone)]
+enum Predicate {
+    Eq(FieldType),
+    Gt(FieldType),
+    Lt(FieldType),
+}
+
+impl Predicate {
+    fn matches(&self, value: &FieldType) -> bool {
+        match self {
+            Predicate::Eq(target) => value == target,
+            Predicate::Gt(target) => value.as_f64() > target.as_f64(),
+            Predicate::Lt(target) => value.as_f64() < target.as_f64(),
+        }
+    }
+}
+
+#[derive(Debug)]
+#[derive(Clone)]
+#[derive(Copy)]
+enum Agg {
+    Sum,
+    Count,
+    Avg,
+    Min,
+    Max,
+}
+
+type Subscriber = (HashSet<String>, Box<dyn FnMut(&Event)>);
+
+#[derive(Debug)]
+enum Event {
+    Added { entity: i64, component: String },
+    Removed { entity: i64, component: String },
+    FieldUpdated { entity: i64, component: String, field: String, old: FieldType, new: FieldType },
 }
 
 #[derive(Debug)]
@@ -37,20 +118,140 @@ struct Entity {
 
 #[derive(Debug)]
 #[derive(Clone)]
+#[derive(Serialize, Deserialize)]
 struct Component {
     fields: HashMap<String, FieldType>
 }
 
+#[derive(Serialize, Deserialize)]
 struct Database {
     next_id: i64,
     entities: Vec<i64>,
-    components: HashMap<String, HashMap<i64, Component>>
+    components: HashMap<String, HashMap<i64, Component>>,
+    #[serde(skip)]
+    component_bits: HashMap<String, usize>,
+    #[serde(skip)]
+    next_bit: usize,
+    #[serde(skip)]
+    entity_signatures: HashMap<i64, u64>,
+    slot_generations: Vec<u32>,
+    free_slots: Vec<i64>,
+    relations: HashMap<String, HashMap<i64, HashSet<i64>>>,
+    #[serde(skip)]
+    subscribers: Vec<Subscriber>,
+}
+
+struct Query<'a> {
+    database: &'a Database,
+    include_mask: u64,
+    exclude_mask: u64,
+    field_predicates: Vec<(String, String, Predicate)>,
+    impossible: bool,
+}
+
+impl<'a> Query<'a> {
+    fn with_component(mut self, component_name: &str) -> Self {
+        match self.database.component_bits.get(component_name) {
+            Some(&bit) => self.include_mask |= 1 << bit,
+            None => self.impossible = true,
+        }
+        self
+    }
+
+    fn without_component(mut self, component_name: &str) -> Self {
+        if let Some(&bit) = self.database.component_bits.get(component_name) {
+            self.exclude_mask |= 1 << bit;
+        }
+        self
+    }
+
+    fn where_field(mut self, component_name: &str, field: &str, predicate: Predicate) -> Self {
+        self.field_predicates.push((component_name.to_string(), field.to_string(), predicate));
+        self
+    }
+
+    fn run(self) -> Vec<Entity> {
+        if self.impossible {
+            return vec![];
+        }
+
+        if self.include_mask == 0 && self.exclude_mask == 0 && self.field_predicates.is_empty() {
+            return self.database.entities.iter().filter_map(|&entity_id| self.database.get_entity(entity_id)).collect();
+        }
+
+        let mut entities = vec![];
+
+        for &entity_id in &self.database.entities {
+            let signature = self.database.entity_signatures.get(&entity_id).copied().unwrap_or(0);
+
+            if signature & self.include_mask != self.include_mask {
+                continue;
+            }
+            if signature & self.exclude_mask != 0 {
+                continue;
+            }
+
+            let entity = match self.database.get_entity(entity_id) {
+                Some(entity) => entity,
+                None => continue,
+            };
+            let matches = self.field_predicates.iter().all(|(component_name, field, predicate)| {
+                entity.components.get(component_name)
+                    .and_then(|component| component.fields.get(field))
+                    .is_some_and(|value| predicate.matches(value))
+            });
+
+            if matches {
+                entities.push(entity);
+            }
+        }
+
+        entities
+    }
 }
 
 impl Database {
+    fn pack_id(slot: i64, generation: u32) -> i64 {
+        (generation as i64) << 32 | slot
+    }
+
+    fn unpack_id(entity_id: i64) -> (i64, u32) {
+        (entity_id & 0xFFFF_FFFF, (entity_id >> 32) as u32)
+    }
+
+    fn is_valid(&self, entity_id: i64) -> bool {
+        let (slot, generation) = Self::unpack_id(entity_id);
+        self.slot_generations.get(slot as usize).is_some_and(|&current_generation| current_generation == generation)
+    }
+
+    // Returns `MAX_COMPONENT_BITS` once every bit of the `u64` signature is taken; callers must
+    // treat that as "no bit available" rather than shifting by it.
+    fn bit_for_component(&mut self, component_name: &str) -> usize {
+        if let Some(&bit) = self.component_bits.get(component_name) {
+            return bit;
+        }
+
+        if self.next_bit >= MAX_COMPONENT_BITS {
+            return MAX_COMPONENT_BITS;
+        }
+
+        let bit = self.next_bit;
+        self.component_bits.insert(component_name.to_string(), bit);
+        self.next_bit += 1;
+        bit
+    }
+
     fn add_entity(&mut self, component_hash: HashMap<String, Component>) -> i64 {
-        let entity_id = self.next_id;
-        self.next_id += 1;
+        let slot = match self.free_slots.pop() {
+            Some(slot) => slot,
+            None => {
+                let slot = self.next_id;
+                self.next_id += 1;
+                self.slot_generations.push(0);
+                slot
+            }
+        };
+        let entity_id = Self::pack_id(slot, self.slot_generations[slot as usize]);
 
         for (component_name, component) in component_hash {
             self.add_component_to_entity(entity_id, component_name, component);
@@ -60,50 +261,169 @@ impl Database {
         entity_id
     }
 
+    fn remove_entity(&mut self, entity_id: i64) -> bool {
+        if !self.is_valid(entity_id) {
+            return false;
+        }
+
+        let (slot, generation) = Self::unpack_id(entity_id);
+
+        let component_names: Vec<String> = self.components.iter()
+            .filter(|(_, component_index)| component_index.contains_key(&entity_id))
+            .map(|(component_name, _)| component_name.clone())
+            .collect();
+        for component_name in component_names {
+            self.remove_component_from_entity(entity_id, &component_name);
+        }
+
+        self.entities.retain(|&id| id != entity_id);
+        self.entity_signatures.remove(&entity_id);
+
+        for targets in self.relations.values_mut() {
+            targets.remove(&entity_id);
+            for sources in targets.values_mut() {
+                sources.remove(&entity_id);
+            }
+        }
+
+        self.slot_generations[slot as usize] = generation + 1;
+        self.free_slots.push(slot);
+
+        true
+    }
+
     fn add_component_to_entity(&mut self, entity_id: i64, component_name: String, component: Component) {
-        self.components.entry(component_name.clone()).or_insert(HashMap::new()).entry(entity_id).or_insert(component);
+        let entry = self.components.entry(component_name.clone()).or_default().entry(entity_id);
+        let inserted = matches!(entry, std::collections::hash_map::Entry::Vacant(_));
+        entry.or_insert(component);
+
+        if !inserted {
+            return;
+        }
+
+        let bit = self.bit_for_component(&component_name);
+        if bit < MAX_COMPONENT_BITS {
+            *self.entity_signatures.entry(entity_id).or_insert(0) |= 1 << bit;
+        }
+
+        self.emit(Event::Added { entity: entity_id, component: component_name });
+    }
+
+    fn remove_component_from_entity(&mut self, entity_id: i64, component_name: &str) -> bool {
+        if !self.is_valid(entity_id) {
+            return false;
+        }
+
+        let removed = self.components.get_mut(component_name).and_then(|component_index| component_index.remove(&entity_id)).is_some();
+
+        if removed {
+            let bit = self.component_bits.get(component_name).copied();
+            if let Some((bit, signature)) = bit.zip(self.entity_signatures.get_mut(&entity_id)) {
+                *signature &= !(1 << bit);
+            }
+
+            self.emit(Event::Removed { entity: entity_id, component: component_name.to_string() });
+        }
+
+        removed
+    }
+
+    fn subscribe(&mut self, filter: HashSet<String>, callback: Box<dyn FnMut(&Event)>) {
+        self.subscribers.push((filter, callback));
     }
 
-    fn get_entity(&self, entity_id: i64) -> Entity {
+    fn emit(&mut self, event: Event) {
+        let component_name = match &event {
+            Event::Added { component, .. } => component,
+            Event::Removed { component, .. } => component,
+            Event::FieldUpdated { component, .. } => component,
+        };
+
+        for (filter, callback) in &mut self.subscribers {
+            if filter.is_empty() || filter.contains(component_name) {
+                callback(&event);
+            }
+        }
+    }
+
+    fn has_component(&self, entity_id: i64, component_name: &str) -> bool {
+        self.components.get(component_name).is_some_and(|component_index| component_index.contains_key(&entity_id))
+    }
+
+    fn relate(&mut self, source: i64, relation_name: &str, target: i64) {
+        self.relations.entry(relation_name.to_string()).or_default()
+            .entry(target).or_default()
+            .insert(source);
+    }
+
+    fn unrelate(&mut self, source: i64, relation_name: &str, target: i64) {
+        if let Some(sources) = self.relations.get_mut(relation_name).and_then(|targets| targets.get_mut(&target)) {
+            sources.remove(&source);
+        }
+    }
+
+    fn targets_of(&self, source: i64, relation_name: &str) -> Vec<i64> {
+        match self.relations.get(relation_name) {
+            Some(targets) => targets.iter()
+                .filter(|(_, sources)| sources.contains(&source))
+                .map(|(&target, _)| target)
+                .collect(),
+            None => vec![],
+        }
+    }
+
+    fn sources_of(&self, target: i64, relation_name: &str) -> Vec<i64> {
+        match self.relations.get(relation_name).and_then(|targets| targets.get(&target)) {
+            Some(sources) => sources.iter().cloned().collect(),
+            None => vec![],
+        }
+    }
+
+    fn get_entity(&self, entity_id: i64) -> Option<Entity> {
+        if !self.is_valid(entity_id) {
+            return None;
+        }
+
         let mut entity = Entity{components: HashMap::new()};
 
         for (component_name, component_index) in &self.components {
-            match component_index.get(&entity_id) {
-                Some(component) => {
-                    entity.components.insert(component_name.clone(), component.clone());
-                },
-                None => {},
+            if let Some(component) = component_index.get(&entity_id) {
+                entity.components.insert(component_name.clone(), component.clone());
             }
         }
 
-        entity
+        Some(entity)
+    }
+
+    fn query(&self) -> Query<'_> {
+        Query {
+            database: self,
+            include_mask: 0,
+            exclude_mask: 0,
+            field_predicates: vec![],
+            impossible: false,
+        }
     }
 
     fn get_entities_with_components(&self, component_names: Vec<String>) -> Vec<Entity> {
-        let mut entity_ids: HashSet<i64> = HashSet::from_iter(self.entities.iter().cloned());
+        let mut query = self.query();
 
         for component_name in &component_names {
-            match self.components.get(component_name) {
-                Some(component_hash) => {
-                    entity_ids = entity_ids.intersection(&component_hash.keys().cloned().collect()).cloned().collect();
-                },
-                None => {
-                    entity_ids.clear();
-                }
-            }
+            query = query.with_component(component_name);
         }
 
-        let mut entities = vec![];
+        query.run()
+    }
 
-        for entity_id in entity_ids {
-            entities.push(self.get_entity(entity_id));
+    fn update_component_field(&mut self, entity_id: i64, component_name: &String, field: &String, value: FieldType) -> bool {
+        if !self.is_valid(entity_id) {
+            return false;
         }
 
-        entities
-    }
+        let old_value = self.get_component_field_value(entity_id, component_name, field);
+        let new_value = value.clone();
 
-    fn update_component_field(&mut self, entity_id: i64, component_name: &String, field: &String, value: FieldType) -> bool {
-        match self.components.get_mut(component_name) {
+        let updated = match self.components.get_mut(component_name) {
             Some(component_hash) => {
                 match component_hash.get_mut(&entity_id) {
                     Some(component) => {
@@ -114,42 +434,114 @@ impl Database {
                 }
             },
             _ => false,
+        };
+
+        if let (true, Some(old_value)) = (updated, old_value) {
+            self.emit(Event::FieldUpdated {
+                entity: entity_id,
+                component: component_name.clone(),
+                field: field.clone(),
+                old: old_value,
+                new: new_value,
+            });
         }
+
+        updated
     }
 
     fn get_component_field_value(&self, entity_id: i64, component_name: &String, field: &String) -> Option<FieldType> {
-        match self.components.get(component_name) {
-            Some(component_hash) => {
-                match component_hash.get(&entity_id) {
-                    Some(component) => {
-                        match component.fields.get(field) {
-                            Some(field) => {
-                                Some(*field)
-                            },
-                            _ => None
-                        }
-                    }
-                    _ => None,
-                }
+        self.components.get(component_name)
+            .and_then(|component_hash| component_hash.get(&entity_id))
+            .and_then(|component| component.fields.get(field))
+            .cloned()
+    }
+
+    fn increment_component_field(&mut self, entity_id: i64, component_name: &String, field: &String, value: FieldType) -> bool {
+        match self.get_component_field_value(entity_id, component_name, field) {
+            Some(current_value) if current_value.is_numeric() && value.is_numeric() => {
+                self.update_component_field(entity_id, component_name, field, current_value + value)
             },
-            _ => None,
+            _ => false,
         }
     }
 
-    fn increment_component_field(&mut self, entity_id: i64, component_name: &String, field: &String, value: FieldType) -> bool {
-        if let Some(current_value) = self.get_component_field_value(entity_id, &component_name, &field) {
-            self.update_component_field(entity_id, &component_name, &field, current_value + value)
-        } else {
-            false
+    fn aggregate_field(&self, component_name: &str, field: &str, agg: Agg) -> Option<FieldType> {
+        let values: Vec<FieldType> = match self.components.get(component_name) {
+            Some(component_index) => component_index.values().filter_map(|component| component.fields.get(field).cloned()).collect(),
+            None => vec![],
+        };
+
+        if values.is_empty() {
+            return match agg {
+                Agg::Count => Some(FieldType::Integer(0)),
+                _ => None,
+            };
+        }
+
+        match agg {
+            Agg::Count => Some(FieldType::Integer(values.len() as i64)),
+            Agg::Sum => Some(values.into_iter().fold(FieldType::Integer(0), |total, value| total + value)),
+            Agg::Avg => {
+                let sum: f64 = values.iter().map(|value| value.as_f64()).sum();
+                Some(FieldType::Float(sum / values.len() as f64))
+            },
+            Agg::Min => values.into_iter().reduce(|a, b| if b.as_f64() < a.as_f64() { b } else { a }),
+            Agg::Max => values.into_iter().reduce(|a, b| if b.as_f64() > a.as_f64() { b } else { a }),
         }
     }
+
+    fn rebuild_index(&mut self) {
+        self.component_bits.clear();
+        self.next_bit = 0;
+        self.entity_signatures.clear();
+
+        let component_names: Vec<String> = self.components.keys().cloned().collect();
+        for component_name in component_names {
+            let entity_ids: Vec<i64> = self.components[&component_name].keys().cloned().collect();
+            let bit = self.bit_for_component(&component_name);
+            if bit >= MAX_COMPONENT_BITS {
+                continue;
+            }
+
+            for entity_id in entity_ids {
+                *self.entity_signatures.entry(entity_id).or_insert(0) |= 1 << bit;
+            }
+        }
+
+        // `slot_generations` and `free_slots` are persisted fields, not derived from
+        // `entities`: a freed slot's bumped generation must survive a save/load round trip,
+        // or a slot recycled after load could reissue an id that collides with a pre-save
+        // handle. Only the signature/bit index, which is cheap to recompute from
+        // `components`, is rebuilt here.
+    }
+
+    fn save_to_path(&self, path: &str) -> std::io::Result<()> {
+        let json = serde_json::to_string_pretty(self).map_err(std::io::Error::other)?;
+        std::fs::write(path, json)
+    }
+
+    fn load_from_path(path: &str) -> std::io::Result<Database> {
+        let json = std::fs::read_to_string(path)?;
+        let mut database: Database = serde_json::from_str(&json).map_err(std::io::Error::other)?;
+
+        database.rebuild_index();
+
+        Ok(database)
+    }
 }
 
 fn main() {
     let mut database = Database {
-        next_id: 1,
+        next_id: 0,
         entities: vec![],
         components: HashMap::new(),
+        component_bits: HashMap::new(),
+        next_bit: 0,
+        entity_signatures: HashMap::new(),
+        slot_generations: vec![],
+        free_slots: vec![],
+        relations: HashMap::new(),
+        subscribers: vec![],
     };
 
     let mut components = HashMap::new();
@@ -168,4 +560,73 @@ fn main() {
     database.increment_component_field(entity_id, &"position".to_string(), &"x".to_string(), FieldType::Float(1.0));
     println!("{:?}", database.get_entities_with_components(vec!["position".to_string()]));
     println!("{:?}", database.get_entities_with_components(vec![]));
+    println!("{:?}", database.query().with_component("position").where_field("position", "x", Predicate::Gt(FieldType::Float(1.0))).run());
+    println!("{:?}", database.query().with_component("position").where_field("position", "x", Predicate::Eq(FieldType::Float(2.0))).run());
+    println!("{:?}", database.query().with_component("position").where_field("position", "x", Predicate::Lt(FieldType::Float(1.0))).run());
+    println!("{:?}", database.query().without_component("position").run());
+
+    database.remove_component_from_entity(entity_id, "position");
+    println!("{:?}", database.has_component(entity_id, "position"));
+    database.remove_entity(entity_id);
+    println!("{:?}", database.get_entity(entity_id));
+
+    let recycled_id = database.add_entity(HashMap::new());
+    println!("{:?}", recycled_id == entity_id);
+
+    database.save_to_path("world.json").expect("failed to save database");
+    let restored = Database::load_from_path("world.json").expect("failed to load database");
+    println!("{:?}", restored.get_entities_with_components(vec![]));
+
+    println!("{:?}", restored.aggregate_field("position", "x", Agg::Sum));
+    println!("{:?}", restored.aggregate_field("position", "x", Agg::Avg));
+    println!("{:?}", restored.aggregate_field("position", "x", Agg::Count));
+    println!("{:?}", restored.aggregate_field("position", "x", Agg::Min));
+    println!("{:?}", restored.aggregate_field("position", "x", Agg::Max));
+
+    let mut world = Database {
+        next_id: 0,
+        entities: vec![],
+        components: HashMap::new(),
+        component_bits: HashMap::new(),
+        next_bit: 0,
+        entity_signatures: HashMap::new(),
+        slot_generations: vec![],
+        free_slots: vec![],
+        relations: HashMap::new(),
+        subscribers: vec![],
+    };
+    let parent_id = world.add_entity(HashMap::new());
+    let child_id = world.add_entity(HashMap::new());
+    world.relate(child_id, "child_of", parent_id);
+    println!("{:?}", world.targets_of(child_id, "child_of"));
+    println!("{:?}", world.sources_of(parent_id, "child_of"));
+    world.unrelate(child_id, "child_of", parent_id);
+    println!("{:?}", world.sources_of(parent_id, "child_of"));
+
+    world.subscribe(HashSet::from(["position".to_string()]), Box::new(|event| match event {
+        Event::Added { entity, component } => println!("added {component} on {entity}"),
+        Event::Removed { entity, component } => println!("removed {component} on {entity}"),
+        Event::FieldUpdated { entity, component, field, old, new } => {
+            println!("{component}.{field} on {entity} changed from {old:?} to {new:?}")
+        },
+    }));
+    let mut fields = HashMap::new();
+    fields.insert("x".to_string(), FieldType::Float(0.0));
+    let tracked_id = world.add_entity(HashMap::from([("position".to_string(), Component{fields})]));
+    world.update_component_field(tracked_id, &"position".to_string(), &"x".to_string(), FieldType::Float(5.0));
+    world.remove_component_from_entity(tracked_id, "position");
+
+    let mut profile_fields = HashMap::new();
+    profile_fields.insert("name".to_string(), FieldType::Text("capybara".to_string()));
+    profile_fields.insert("active".to_string(), FieldType::Boolean(true));
+    profile_fields.insert("tags".to_string(), FieldType::List(vec![FieldType::Text("a".to_string()), FieldType::Text("b".to_string())]));
+    let profile_id = world.add_entity(HashMap::from([("profile".to_string(), Component{fields: profile_fields})]));
+
+    println!("{:?}", world.get_component_field_value(profile_id, &"profile".to_string(), &"name".to_string()).and_then(|value| value.get_text().map(|text| text.to_string())));
+    println!("{:?}", world.increment_component_field(profile_id, &"profile".to_string(), &"active".to_string(), FieldType::Integer(1)));
+
+    let score_fields = HashMap::from([("value".to_string(), FieldType::Integer(10)), ("ratio".to_string(), FieldType::Float(0.5))]);
+    let score_id = world.add_entity(HashMap::from([("score".to_string(), Component{fields: score_fields})]));
+    println!("{:?}", world.get_component_field_value(score_id, &"score".to_string(), &"value".to_string()).and_then(|value| value.get_int()));
+    println!("{:?}", world.get_component_field_value(score_id, &"score".to_string(), &"ratio".to_string()).and_then(|value| value.get_float()));
 }